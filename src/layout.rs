@@ -0,0 +1,220 @@
+//! Declarative multi-partition disk layouts loaded from a `--config` TOML file.
+//!
+//! Modeled on systemd-repart's `.repart.d` drop-ins: the file is an ordered
+//! list of `[[partition]]` tables, each describing one partition. Partitions
+//! are laid out back-to-back in declaration order, 1-MiB aligned, starting
+//! right after the padding region that `main` already reserves.
+
+use anyhow::{Context, Result};
+use gpt::partition_types::{self, Type};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Root of a `--config layout.toml` file.
+#[derive(Debug, Deserialize)]
+pub struct LayoutConfig {
+    #[serde(rename = "partition", default)]
+    pub partitions: Vec<PartitionConfig>,
+}
+
+/// One `[[partition]]` entry.
+#[derive(Debug, Deserialize)]
+pub struct PartitionConfig {
+    /// GPT partition type: a raw GUID, or a shorthand (`esp`, `linux-root`, `linux-home`, `swap`).
+    #[serde(rename = "type")]
+    pub type_: String,
+
+    /// GPT partition label (PARTLABEL), also used as the filesystem label when
+    /// the partition is formatted as `fat32` or `ext4`.
+    pub label: String,
+
+    /// Explicit partition size in MiB. Required unless `source_image` is set or the
+    /// partition grows to fill free space.
+    pub size_mib: Option<u64>,
+
+    /// Raw image to copy into the partition byte-for-byte once it's been laid out.
+    pub source_image: Option<PathBuf>,
+
+    /// How to populate the partition: `fat32`, `ext4`, or `raw` (copy `source_image`
+    /// verbatim, no filesystem created). Defaults to `raw`. Ignored when
+    /// `source_image` is set: a source image is always copied in verbatim.
+    pub format: Option<String>,
+
+    /// Absorb whatever free space is left on the disk instead of taking a fixed
+    /// size, like systemd-repart's growing partitions. Must be the last partition
+    /// in the layout, and requires `--disk-size-mib` on the command line.
+    #[serde(default)]
+    pub grow: bool,
+
+    /// Lower bound (MiB) on the size a `grow` partition may shrink to.
+    pub size_min_mib: Option<u64>,
+
+    /// Upper bound (MiB) on the size a `grow` partition may expand to.
+    pub size_max_mib: Option<u64>,
+}
+
+impl LayoutConfig {
+    /// Load and parse a layout config from disk.
+    pub fn load(path: &Path) -> Result<LayoutConfig> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read layout config {}", path.display()))?;
+        let config: LayoutConfig = toml::from_str(&text)
+            .with_context(|| format!("Failed to parse layout config {}", path.display()))?;
+
+        if config.partitions.is_empty() {
+            anyhow::bail!("Layout config {} defines no partitions", path.display());
+        }
+
+        Ok(config)
+    }
+}
+
+impl PartitionConfig {
+    /// Resolve `type` to a concrete GPT partition type, accepting the shorthand
+    /// aliases alongside raw GUIDs.
+    pub fn resolve_type(&self) -> Result<Type> {
+        Ok(match self.type_.as_str() {
+            "esp" => partition_types::EFI,
+            "linux-root" => partition_types::LINUX_FS,
+            "linux-home" => partition_types::LINUX_HOME,
+            "swap" => partition_types::LINUX_SWAP,
+            guid => Type::from_str(guid)
+                .map_err(|e| anyhow::anyhow!(e))
+                .with_context(|| format!("Invalid partition type '{}'", guid))?,
+        })
+    }
+
+    /// How to populate this partition once it has been given a slot on disk.
+    pub fn format_kind(&self) -> &str {
+        self.format.as_deref().unwrap_or("raw")
+    }
+}
+
+/// A partition after geometry has been resolved: concrete LBAs and a resolved type.
+#[derive(Debug)]
+pub struct PlannedPartition {
+    pub label: String,
+    pub type_guid: Type,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub format: String,
+    pub source_image: Option<PathBuf>,
+}
+
+impl PlannedPartition {
+    pub fn size_bytes(&self, lba_size: u64) -> u64 {
+        (self.last_lba - self.first_lba + 1) * lba_size
+    }
+}
+
+/// Lay out `partitions` back-to-back, 1-MiB aligned, starting at `start_lba`.
+///
+/// Every non-growing partition must carry an explicit `size_mib` or a
+/// `source_image` to size itself from. At most one partition may set
+/// `grow = true`, and it must be the last partition in the layout; it absorbs
+/// whatever free space remains up to `end_lba_budget` (the last usable LBA
+/// before the GPT footer reservation), capped at its `size_max_mib`; if less
+/// than its `size_min_mib` is left, planning fails. `end_lba_budget` is
+/// required if any partition grows.
+pub fn plan_partitions(
+    partitions: &[PartitionConfig],
+    start_lba: u64,
+    lba_size: u64,
+    end_lba_budget: Option<u64>,
+) -> Result<Vec<PlannedPartition>> {
+    let align_lbas = (1024 * 1024 / lba_size).max(1);
+    let mut cursor = start_lba;
+    let mut planned = Vec::with_capacity(partitions.len());
+
+    if let Some(pos) = partitions.iter().position(|p| p.grow) {
+        anyhow::ensure!(
+            pos == partitions.len() - 1,
+            "Growing partition '{}' must be the last partition in the layout",
+            partitions[pos].label
+        );
+    }
+
+    for part in partitions {
+        // Align the start of every partition to 1 MiB, like systemd-repart does.
+        if !cursor.is_multiple_of(align_lbas) {
+            cursor += align_lbas - (cursor % align_lbas);
+        }
+
+        let size_lbas = if part.grow {
+            let budget = end_lba_budget.context(
+                "A partition has grow = true, but no disk size budget was given (pass --disk-size-mib)",
+            )?;
+            if budget < cursor {
+                anyhow::bail!(
+                    "Partition '{}' has no room to grow into: the disk is smaller than the partitions before it",
+                    part.label
+                );
+            }
+            let mut grow_lbas = budget - cursor + 1;
+
+            if let Some(min_mib) = part.size_min_mib {
+                let min_lbas = ((min_mib * 1024 * 1024) as f64 / lba_size as f64).ceil() as u64;
+                if grow_lbas < min_lbas {
+                    anyhow::bail!(
+                        "Partition '{}' would grow to only {} MiB, below its size_min_mib of {}",
+                        part.label,
+                        grow_lbas * lba_size / 1024 / 1024,
+                        min_mib
+                    );
+                }
+            }
+            if let Some(max_mib) = part.size_max_mib {
+                let max_lbas = ((max_mib * 1024 * 1024) as f64 / lba_size as f64).ceil() as u64;
+                grow_lbas = grow_lbas.min(max_lbas);
+            }
+
+            grow_lbas
+        } else if let Some(size_mib) = part.size_mib {
+            ((size_mib * 1024 * 1024) as f64 / lba_size as f64).ceil() as u64
+        } else if let Some(source) = &part.source_image {
+            let size_bytes = fs::metadata(source)
+                .with_context(|| format!("Failed to stat source image {}", source.display()))?
+                .len();
+            (size_bytes as f64 / lba_size as f64).ceil() as u64
+        } else {
+            anyhow::bail!(
+                "Partition '{}' needs either size_mib, source_image, or grow = true",
+                part.label
+            );
+        };
+
+        if size_lbas == 0 {
+            anyhow::bail!("Partition '{}' resolved to zero size", part.label);
+        }
+
+        let first_lba = cursor;
+        let last_lba = first_lba + size_lbas - 1;
+        cursor = last_lba + 1;
+
+        planned.push(PlannedPartition {
+            label: part.label.clone(),
+            type_guid: part.resolve_type()?,
+            first_lba,
+            last_lba,
+            format: part.format_kind().to_string(),
+            source_image: part.source_image.clone(),
+        });
+    }
+
+    // Growing partitions are clamped to the budget above, but fixed-size
+    // partitions aren't: make sure the layout as a whole still fits within
+    // --disk-size-mib instead of silently overrunning into the footer
+    // reservation (or past the end of the disk entirely).
+    if let (Some(budget), Some(last)) = (end_lba_budget, planned.last()) {
+        anyhow::ensure!(
+            last.last_lba <= budget,
+            "Layout needs {} sectors but --disk-size-mib only budgets {} sectors for partitions",
+            last.last_lba - start_lba + 1,
+            budget - start_lba + 1
+        );
+    }
+
+    Ok(planned)
+}
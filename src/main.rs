@@ -1,30 +1,42 @@
 use anyhow::{Context, Result};
 use clap::Parser;
-use fatfs::{FatType, FormatVolumeOptions, format_volume};
+use fatfs::{Dir, FatType, FileSystem, FormatVolumeOptions, FsOptions, ReadWriteSeek, format_volume};
 use fscommon::{BufStream, StreamSlice};
 use gpt::{GptConfig, disk::LogicalBlockSize, partition_types};
 use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::str::FromStr;
 use uuid::Uuid;
 
+mod layout;
+mod seed;
+mod verity;
+
 const PART_NAME_ESP: &str = "efiesp";
 const PART_NAME_LINUX: &str = "linux";
+const PART_NAME_VERITY: &str = "linuxverity";
 
 // Specified Partition GUIDs
 const PART_UUID_ESP: &str = "dd72ae4a-b812-4f7c-b59f-2bc5395d3aab";
 const PART_UUID_LINUX: &str = "1f64a68b-eb12-443b-a55e-5aad64c3b432";
 
+// "Linux dm-verity" GPT partition type GUID (per the Discoverable Partitions Spec)
+const PART_TYPE_LINUX_VERITY_GUID: &str = "2c7357ed-ebd2-46d9-aec1-23d437ec2bf5";
+
 /// A tool to prepend empty space and wrap a raw filesystem image in a GPT partition table.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Input raw filesystem image (e.g., rootfs.ext4)
+    /// Input raw filesystem image (e.g., rootfs.ext4). Required unless --config
+    /// is given, since a declarative layout sources its own partition content
+    /// via each partition's `source_image`.
     #[arg(short, long)]
-    input: PathBuf,
+    input: Option<PathBuf>,
 
     /// Output disk image path
     #[arg(short, long)]
@@ -41,18 +53,88 @@ struct Args {
     /// Logical Block Address size (512 or 4096)
     #[arg(long, default_value_t = 512)]
     lba: u64,
+
+    /// Build the image from a declarative partition layout (TOML) instead of the
+    /// built-in single-data-partition-plus-ESP layout
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Target total disk size in MiB for --config mode. Required if any partition
+    /// in the layout sets grow = true; otherwise the disk is sized to exactly fit
+    /// the declared partitions plus padding and footer.
+    #[arg(long)]
+    disk_size_mib: Option<u64>,
+
+    /// Derive the disk GUID and every partition GUID deterministically from this
+    /// seed UUID instead of generating them randomly, so the same inputs always
+    /// produce a byte-identical image. Partitions with format = "ext4" in
+    /// --config mode are not covered: mkfs.ext4 is run without a fixed UUID or
+    /// timestamps, so their contents differ between runs
+    #[arg(long)]
+    seed: Option<Uuid>,
+
+    /// Build a dm-verity hash tree over the Linux data partition and store it in
+    /// an additional partition, tagged with the Linux dm-verity GPT type GUID
+    #[arg(long)]
+    verity: bool,
+
+    /// Also write a hybrid/protective MBR into LBA 0, mirroring the GPT entries,
+    /// so the image boots on BIOS-only hypervisors that don't consult the GPT
+    #[arg(long)]
+    hybrid_mbr: bool,
+
+    /// Recursively copy this host directory's contents into the ESP after
+    /// formatting it (e.g. a tree containing EFI/BOOT/BOOTX64.EFI), so the image
+    /// is directly bootable. Requires --esp
+    #[arg(long)]
+    esp_copy: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Parse the constant UUIDs
-    let uuid_esp = Uuid::from_str(PART_UUID_ESP).context("Failed to parse internal ESP UUID")?;
-    let uuid_linux =
-        Uuid::from_str(PART_UUID_LINUX).context("Failed to parse internal Linux UUID")?;
+    if let Some(config_path) = &args.config {
+        anyhow::ensure!(
+            !args.verity,
+            "--verity is not yet supported in --config mode; add a verity partition to the layout instead"
+        );
+        anyhow::ensure!(
+            !args.hybrid_mbr,
+            "--hybrid-mbr is not yet supported in --config mode"
+        );
+        anyhow::ensure!(
+            args.esp_copy.is_none(),
+            "--esp-copy is not yet supported in --config mode; use a 'fat32' partition with a source_image instead"
+        );
+        return run_config_mode(&args, config_path);
+    }
+
+    let input_path = args
+        .input
+        .as_ref()
+        .context("--input is required unless --config is given")?;
+
+    anyhow::ensure!(
+        args.disk_size_mib.is_none(),
+        "--disk-size-mib only applies to --config mode; the legacy layout always sizes the disk to exactly fit the input image"
+    );
+
+    // Parse the constant UUIDs, or derive them from --seed if one was given.
+    let (uuid_esp, uuid_linux, uuid_disk) = match &args.seed {
+        Some(seed) => (
+            seed::derive_uuid(seed, "esp"),
+            seed::derive_uuid(seed, "linux"),
+            Some(seed::derive_uuid(seed, "disk")),
+        ),
+        None => (
+            Uuid::from_str(PART_UUID_ESP).context("Failed to parse internal ESP UUID")?,
+            Uuid::from_str(PART_UUID_LINUX).context("Failed to parse internal Linux UUID")?,
+            None,
+        ),
+    };
 
     // 1. Validate and Analyze Input
-    let input_file = File::open(&args.input).context("Failed to open input file")?;
+    let input_file = File::open(input_path).context("Failed to open input file")?;
     let input_len = input_file.metadata()?.len();
 
     println!("Input image size: {} bytes", input_len);
@@ -61,6 +143,10 @@ fn main() -> Result<()> {
         anyhow::bail!("LBA must be 512 or 4096");
     }
 
+    if args.esp_copy.is_some() && !args.esp {
+        anyhow::bail!("--esp-copy requires --esp");
+    }
+
     // Check alignment
     if input_len % args.lba != 0 {
         println!(
@@ -69,18 +155,57 @@ fn main() -> Result<()> {
         );
     }
 
+    // If requested, build the dm-verity hash tree now, straight off the input
+    // file, before geometry is calculated: the hash partition's size depends on
+    // the tree's size, which is already fully known at this point.
+    let verity_tree = if args.verity {
+        println!("Building dm-verity hash tree over input image...");
+        let salt = match &args.seed {
+            Some(seed) => seed::derive_uuid(seed, "verity-salt").as_bytes().to_vec(),
+            None => Uuid::new_v4().as_bytes().to_vec(),
+        };
+        let reader = BufReader::new(
+            File::open(input_path).context("Failed to open input file for verity hashing")?,
+        );
+        Some(verity::VerityTree::build(reader, input_len, salt)?)
+    } else {
+        None
+    };
+
     // 2. Calculate Geometry
     let pad_bytes = args.pad_mib * 1024 * 1024;
 
+    // The data partition must cover at least as many bytes as the verity tree
+    // was hashed over (a whole number of 4096-byte blocks), or dm-verity would
+    // read past the end of the partition at verification time.
+    let data_size_bytes = match &verity_tree {
+        Some(tree) => input_len.max(tree.data_block_count * verity::BLOCK_SIZE),
+        None => input_len,
+    };
+
     // Calculate required LBAs
     let padding_lbas = pad_bytes / args.lba;
-    let data_lbas = (input_len as f64 / args.lba as f64).ceil() as u64;
+    let data_lbas = (data_size_bytes as f64 / args.lba as f64).ceil() as u64;
 
     // GPT requires 33 LBAs at the end for the backup header (usually 1 header + 32 entries)
     // We add a bit of safety margin (1 MiB) at the end to avoid edge cases
     let footer_lbas = (1024 * 1024) / args.lba;
 
-    let total_lbas = padding_lbas + data_lbas + footer_lbas;
+    // The verity hash partition (if any) is 1-MiB aligned and sits right after
+    // the data partition, ahead of the footer reservation.
+    let align_lbas = (1024 * 1024) / args.lba;
+    let verity_lbas = verity_tree
+        .as_ref()
+        .map(|tree| (tree.partition_size_bytes() as f64 / args.lba as f64).ceil() as u64)
+        .unwrap_or(0);
+    let verity_padding_lbas = if verity_tree.is_some() {
+        align_lbas
+    } else {
+        0
+    };
+
+    let total_lbas =
+        padding_lbas + data_lbas + verity_padding_lbas + verity_lbas + footer_lbas;
     let total_bytes = total_lbas * args.lba;
 
     println!("Configuration:");
@@ -92,6 +217,13 @@ fn main() -> Result<()> {
         "  Data Length:  {} bytes ({} sectors)",
         input_len, data_lbas
     );
+    if let Some(tree) = &verity_tree {
+        println!(
+            "  Verity Hash:  {} bytes ({} sectors)",
+            tree.partition_size_bytes(),
+            verity_lbas
+        );
+    }
     println!("  Footer Space: {} sectors", footer_lbas);
     println!("  Total Size:   {} bytes", total_bytes);
 
@@ -126,11 +258,32 @@ fn main() -> Result<()> {
     disk.update_partitions(std::collections::BTreeMap::new())
         .context("Failed to initialize GPT header")?;
 
+    if let Some(disk_guid) = uuid_disk {
+        disk.update_guid(Some(disk_guid));
+    }
+
     // Define Data Partition Geometry
     // It always starts after the padding
     let data_start_lba = padding_lbas;
     let data_end_lba = data_start_lba + data_lbas - 1;
 
+    // The verity hash partition (if any) follows right after the data partition,
+    // 1-MiB aligned (the reservation in verity_padding_lbas above covers the
+    // worst-case alignment gap this introduces).
+    let verity_start_lba = {
+        let start = data_end_lba + 1;
+        if start.is_multiple_of(align_lbas) {
+            start
+        } else {
+            start + align_lbas - (start % align_lbas)
+        }
+    };
+    let verity_end_lba = verity_start_lba + verity_lbas.saturating_sub(1);
+    let uuid_verity = match &args.seed {
+        Some(seed) => seed::derive_uuid(seed, "verity"),
+        None => Uuid::new_v4(),
+    };
+
     let mut esp_region: Option<(u64, u64)> = None;
     if args.esp {
         // ESP Logic
@@ -165,7 +318,7 @@ fn main() -> Result<()> {
         let _ = disk
             .add_partition(
                 PART_NAME_LINUX,
-                input_len,
+                data_size_bytes,
                 partition_types::LINUX_FS,
                 0,
                 None,
@@ -174,6 +327,22 @@ fn main() -> Result<()> {
 
         println!("     Linux Data GUID: {}", uuid_linux);
 
+        // 3. Add the verity hash partition, if requested
+        if let Some(tree) = &verity_tree {
+            let verity_type = partition_types::Type::from_str(PART_TYPE_LINUX_VERITY_GUID)
+                .map_err(|e| anyhow::anyhow!(e))
+                .context("Invalid verity partition type GUID")?;
+            let _ = disk
+                .add_partition(
+                    PART_NAME_VERITY,
+                    tree.partition_size_bytes(),
+                    verity_type,
+                    0,
+                    None,
+                )
+                .context("Failed to add verity partition entry")?;
+        }
+
         // Manually enforce exact LBAs
         let mut partitions = disk.partitions().clone();
 
@@ -187,6 +356,12 @@ fn main() -> Result<()> {
             p.last_lba = data_end_lba;
             p.part_guid = uuid_linux;
         }
+        // Entry 3 only exists when the verity partition was added above.
+        if let Some(p) = partitions.get_mut(&3) {
+            p.first_lba = verity_start_lba;
+            p.last_lba = verity_end_lba;
+            p.part_guid = uuid_verity;
+        }
 
         disk.update_partitions(partitions)
             .context("Failed to update partitions")?;
@@ -195,7 +370,7 @@ fn main() -> Result<()> {
         let _ = disk
             .add_partition(
                 PART_NAME_LINUX,
-                input_len,
+                data_size_bytes,
                 partition_types::LINUX_FS,
                 0,
                 None,
@@ -204,6 +379,21 @@ fn main() -> Result<()> {
 
         println!("     Linux Data GUID: {}", uuid_linux);
 
+        if let Some(tree) = &verity_tree {
+            let verity_type = partition_types::Type::from_str(PART_TYPE_LINUX_VERITY_GUID)
+                .map_err(|e| anyhow::anyhow!(e))
+                .context("Invalid verity partition type GUID")?;
+            let _ = disk
+                .add_partition(
+                    PART_NAME_VERITY,
+                    tree.partition_size_bytes(),
+                    verity_type,
+                    0,
+                    None,
+                )
+                .context("Failed to add verity partition entry")?;
+        }
+
         // Manually enforce start at padding
         let mut partitions = disk.partitions().clone();
         let part = partitions.get_mut(&1).context("Partition 1 not found")?;
@@ -211,6 +401,13 @@ fn main() -> Result<()> {
         part.last_lba = data_end_lba;
         part.part_guid = uuid_linux;
 
+        // Entry 2 only exists when the verity partition was added above.
+        if let Some(p) = partitions.get_mut(&2) {
+            p.first_lba = verity_start_lba;
+            p.last_lba = verity_end_lba;
+            p.part_guid = uuid_verity;
+        }
+
         disk.update_partitions(partitions)
             .context("Failed to update partitions")?;
     }
@@ -218,15 +415,36 @@ fn main() -> Result<()> {
     disk.write()
         .context("Failed to write GPT changes to disk")?;
 
+    if args.hybrid_mbr {
+        println!("Writing hybrid MBR...");
+        let esp_mbr_region = esp_region.map(|(start_lba, size_bytes)| (start_lba, size_bytes / args.lba));
+        write_hybrid_mbr(
+            &args.output,
+            esp_mbr_region,
+            (data_start_lba, data_lbas),
+            total_lbas,
+        )
+        .context("Failed to write hybrid MBR")?;
+    }
+
     if let Some((esp_start_lba, esp_size_bytes)) = esp_region {
         println!("Formatting ESP as FAT32...");
-        format_esp_partition(
+        let esp_fs = format_fat32_partition(
             &args.output,
             esp_start_lba * args.lba,
             esp_size_bytes,
             args.lba,
+            b"EFI SYSTEM ",
         )
         .context("Failed to format ESP partition")?;
+
+        if let Some(esp_copy_dir) = &args.esp_copy {
+            println!("Copying {} into ESP...", esp_copy_dir.display());
+            copy_dir_into_fatfs(&esp_fs, esp_copy_dir)
+                .context("Failed to copy --esp-copy directory into ESP")?;
+        }
+
+        esp_fs.unmount().context("Failed to flush formatted ESP")?;
     }
 
     // 5. Copy Data (Sparse Copy)
@@ -267,30 +485,116 @@ fn main() -> Result<()> {
     pb.finish_with_message("Copy complete");
     writer.flush()?;
 
+    if let Some(tree) = &verity_tree {
+        println!("Writing dm-verity hash partition...");
+        writer.seek(SeekFrom::Start(verity_start_lba * args.lba))?;
+        writer.write_all(&tree.to_partition_bytes())?;
+        writer.flush()?;
+    }
+
     println!("Done! Created {}", args.output.display());
     if args.esp {
         println!("Note: ESP partition formatted as FAT32 with label EFI SYSTEM.");
     }
+    if let Some(tree) = &verity_tree {
+        println!("Verity root hash: {}", tree.root_hash_hex());
+    }
 
     // Print fstab entries
     println!("\nRecommended /etc/fstab entries:");
     println!("# <file system> <mount point> <type> <options> <dump> <pass>");
     println!("proc /proc proc defaults 0 0");
     if args.esp {
-        println!("PARTLABEL={} /boot/efi vfat umask=0077 0 1", PART_NAME_ESP);
+        println!("PARTLABEL={} /boot/efi vfat umask=0077 0 2", PART_NAME_ESP);
     }
     // Using PARTUUID because we are referencing the GPT Partition GUID, not the filesystem UUID.
     println!("PARTUUID={} / ext4 errors=remount-ro 0 1", uuid_linux);
 
+    if let Some(tree) = &verity_tree {
+        println!(
+            "\nSample kernel command line: roothash={}",
+            tree.root_hash_hex()
+        );
+        println!(
+            "Sample fstab verity entry: PARTUUID={} /boot/verity.img verity roothash={},hashdevice=PARTUUID={} 0 0",
+            uuid_linux,
+            tree.root_hash_hex(),
+            uuid_verity
+        );
+    }
+
     Ok(())
 }
 
-fn format_esp_partition(
+/// Write a hybrid MBR into LBA 0: a protective entry (type 0xEE) covering the
+/// GPT, plus real MBR entries mirroring the ESP (0xEF) and Linux data (0x83)
+/// partitions at the same LBAs as their GPT entries. This lets the image boot
+/// on BIOS-only hypervisors and machine types that don't consult the GPT.
+///
+/// All LBAs here are expressed in the same logical block size as the rest of
+/// the disk, not the 512-byte sectors a strict MBR implementation would use.
+fn write_hybrid_mbr(
+    image_path: &Path,
+    esp_region: Option<(u64, u64)>,
+    data_region: (u64, u64),
+    total_lbas: u64,
+) -> Result<()> {
+    let mut mbr = [0u8; 512];
+
+    let write_entry = |mbr: &mut [u8; 512],
+                        slot: usize,
+                        bootable: bool,
+                        part_type: u8,
+                        start_lba: u64,
+                        size_lba: u64| {
+        let offset = 446 + slot * 16;
+        mbr[offset] = if bootable { 0x80 } else { 0x00 };
+        mbr[offset + 1..offset + 4].copy_from_slice(&[0xFF, 0xFF, 0xFF]); // CHS start: use LBA
+        mbr[offset + 4] = part_type;
+        mbr[offset + 5..offset + 8].copy_from_slice(&[0xFF, 0xFF, 0xFF]); // CHS end: use LBA
+        mbr[offset + 8..offset + 12].copy_from_slice(&(start_lba as u32).to_le_bytes());
+        mbr[offset + 12..offset + 16]
+            .copy_from_slice(&(size_lba.min(u32::MAX as u64) as u32).to_le_bytes());
+    };
+
+    // Entry 0: protective GPT entry, covering the whole disk past LBA 0.
+    write_entry(&mut mbr, 0, false, 0xEE, 1, total_lbas - 1);
+
+    let mut slot = 1;
+    if let Some((esp_start_lba, esp_size_lba)) = esp_region {
+        write_entry(&mut mbr, slot, false, 0xEF, esp_start_lba, esp_size_lba);
+        slot += 1;
+    }
+
+    let (data_start_lba, data_size_lba) = data_region;
+    write_entry(&mut mbr, slot, true, 0x83, data_start_lba, data_size_lba);
+
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(image_path)
+        .with_context(|| format!("Failed to open {}", image_path.display()))?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&mbr)
+        .context("Failed to write hybrid MBR to LBA 0")?;
+
+    Ok(())
+}
+
+/// Format a partition as FAT32 and mount it, returning the mounted
+/// [`fatfs::FileSystem`] so callers can write directory entries (e.g. via
+/// `--esp-copy`) on the very same [`StreamSlice`] before flushing, instead of
+/// formatting, closing, and reopening for a second mount-and-copy pass.
+/// Callers are responsible for calling `.unmount()` when they're done.
+fn format_fat32_partition(
     image_path: &Path,
     start_offset: u64,
     size_bytes: u64,
     lba_size: u64,
-) -> Result<()> {
+    volume_label: &[u8; 11],
+) -> Result<FileSystem<BufStream<StreamSlice<File>>>> {
     if lba_size > u16::MAX as u64 {
         anyhow::bail!("LBA size {} exceeds FAT formatter limit", lba_size);
     }
@@ -312,13 +616,321 @@ fn format_esp_partition(
     // Format the partition as FAT32
     let options = FormatVolumeOptions::new()
         .fat_type(FatType::Fat32)
-        .volume_label(*b"EFI SYSTEM ")
+        .volume_label(*volume_label)
         .bytes_per_sector(lba_size as u16);
 
     format_volume(&mut buf_partition, options).context("fatfs formatting failed")?;
-    buf_partition
-        .flush()
-        .context("Failed to flush formatted ESP")?;
+
+    FileSystem::new(buf_partition, FsOptions::new())
+        .context("Failed to mount freshly formatted FAT32 volume")
+}
+
+/// Recursively copy a host directory tree into the root of a freshly formatted
+/// FAT filesystem, for `--esp-copy`.
+fn copy_dir_into_fatfs<IO: ReadWriteSeek>(
+    fs: &FileSystem<IO>,
+    host_dir: &Path,
+) -> Result<()> {
+    copy_dir_entries(&fs.root_dir(), host_dir)
+}
+
+fn copy_dir_entries<IO: ReadWriteSeek>(
+    fat_dir: &Dir<IO>,
+    host_dir: &Path,
+) -> Result<()> {
+    for entry in std::fs::read_dir(host_dir)
+        .with_context(|| format!("Failed to read directory {}", host_dir.display()))?
+    {
+        let entry = entry?;
+        let host_path = entry.path();
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .with_context(|| format!("Non-UTF8 file name in {}", host_path.display()))?;
+
+        if entry.file_type()?.is_dir() {
+            let sub_dir = fat_dir
+                .create_dir(name)
+                .with_context(|| format!("Failed to create directory '{}' in ESP", name))?;
+            copy_dir_entries(&sub_dir, &host_path)?;
+        } else {
+            let mut dest_file = fat_dir
+                .create_file(name)
+                .with_context(|| format!("Failed to create file '{}' in ESP", name))?;
+            let mut src_file = File::open(&host_path)
+                .with_context(|| format!("Failed to open {}", host_path.display()))?;
+            std::io::copy(&mut src_file, &mut dest_file)
+                .with_context(|| format!("Failed to copy {} into ESP", host_path.display()))?;
+            dest_file
+                .flush()
+                .with_context(|| format!("Failed to flush '{}' in ESP", name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Pad or truncate a partition label to the 11-byte FAT volume label field,
+/// upper-cased as FAT32 expects.
+fn fat_volume_label(label: &str) -> [u8; 11] {
+    let mut bytes = [b' '; 11];
+    for (dst, src) in bytes.iter_mut().zip(label.to_ascii_uppercase().bytes()) {
+        *dst = src;
+    }
+    bytes
+}
+
+/// Create an ext4 filesystem inside the given byte range of `image_path` by
+/// attaching it as a loop device and shelling out to `mkfs.ext4` (there is no
+/// pure-Rust ext4 formatter equivalent to the `fatfs` crate).
+fn format_ext4_partition(
+    image_path: &Path,
+    start_offset: u64,
+    size_bytes: u64,
+    label: &str,
+) -> Result<()> {
+    let output = Command::new("losetup")
+        .args(["--find", "--show", "--offset"])
+        .arg(start_offset.to_string())
+        .arg("--sizelimit")
+        .arg(size_bytes.to_string())
+        .arg(image_path)
+        .output()
+        .context("Failed to run losetup (is it installed and are we root?)")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "losetup failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let loop_dev = String::from_utf8(output.stdout)
+        .context("losetup produced non-UTF8 output")?
+        .trim()
+        .to_string();
+
+    let result = Command::new("mkfs.ext4")
+        .args(["-F", "-L", label])
+        .arg(&loop_dev)
+        .status()
+        .context("Failed to run mkfs.ext4");
+
+    // Always detach the loop device, even if mkfs.ext4 failed.
+    let _ = Command::new("losetup").args(["-d", &loop_dev]).status();
+
+    match result {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => anyhow::bail!("mkfs.ext4 exited with {}", status),
+        Err(err) => Err(err),
+    }
+}
+
+/// Copy a source file verbatim into a byte range of `image_path`, for any
+/// partition with a `source_image` (its declared `format` is not applied).
+fn copy_raw_into_partition(
+    image_path: &Path,
+    source_path: &Path,
+    start_offset: u64,
+    size_bytes: u64,
+) -> Result<()> {
+    let mut source = BufReader::new(
+        File::open(source_path)
+            .with_context(|| format!("Failed to open source image {}", source_path.display()))?,
+    );
+    let source_len = source.get_ref().metadata()?.len();
+    if source_len > size_bytes {
+        anyhow::bail!(
+            "Source image {} ({} bytes) does not fit in its partition ({} bytes)",
+            source_path.display(),
+            source_len,
+            size_bytes
+        );
+    }
+
+    let mut output = OpenOptions::new()
+        .write(true)
+        .open(image_path)
+        .with_context(|| format!("Failed to open {}", image_path.display()))?;
+    output.seek(SeekFrom::Start(start_offset))?;
+
+    std::io::copy(&mut source, &mut output)
+        .with_context(|| format!("Failed to copy {} into partition", source_path.display()))?;
+
+    Ok(())
+}
+
+/// Populate a single planned partition per its declared format, after GPT
+/// geometry has been written to disk.
+fn populate_partition(image_path: &Path, part: &layout::PlannedPartition, lba_size: u64) -> Result<()> {
+    let start_offset = part.first_lba * lba_size;
+    let size_bytes = part.size_bytes(lba_size);
+
+    if let Some(source) = &part.source_image {
+        println!("  -> Copying {} into '{}'", source.display(), part.label);
+        return copy_raw_into_partition(image_path, source, start_offset, size_bytes);
+    }
+
+    match part.format.as_str() {
+        "fat32" => {
+            println!("  -> Formatting '{}' as FAT32", part.label);
+            let fs = format_fat32_partition(
+                image_path,
+                start_offset,
+                size_bytes,
+                lba_size,
+                &fat_volume_label(&part.label),
+            )?;
+            fs.unmount()
+                .with_context(|| format!("Failed to flush formatted partition '{}'", part.label))
+        }
+        "ext4" => {
+            println!("  -> Formatting '{}' as ext4", part.label);
+            format_ext4_partition(image_path, start_offset, size_bytes, &part.label)
+        }
+        "raw" => Ok(()),
+        other => anyhow::bail!("Unknown partition format '{}'", other),
+    }
+}
+
+/// Best-effort fstab entry for a planned partition, based on its GPT type.
+fn fstab_line_for(part: &layout::PlannedPartition, part_guid: Uuid) -> Option<String> {
+    // fsck pass: the root filesystem checks first (1), other real filesystems
+    // check after (2), and swap never gets fsck'd (0).
+    let (mount_point, fs_type, options, pass) = if part.type_guid == partition_types::EFI {
+        ("/boot/efi", "vfat", "umask=0077", 2)
+    } else if part.type_guid == partition_types::LINUX_FS {
+        ("/", "ext4", "errors=remount-ro", 1)
+    } else if part.type_guid == partition_types::LINUX_HOME {
+        ("/home", "ext4", "defaults", 2)
+    } else if part.type_guid == partition_types::LINUX_SWAP {
+        ("none", "swap", "sw", 0)
+    } else {
+        return None;
+    };
+
+    Some(format!(
+        "PARTUUID={} {} {} {} 0 {}",
+        part_guid, mount_point, fs_type, options, pass
+    ))
+}
+
+/// Build an image from a declarative `--config layout.toml` partition list,
+/// instead of the built-in single-data-partition-plus-ESP layout.
+fn run_config_mode(args: &Args, config_path: &Path) -> Result<()> {
+    let layout_config = layout::LayoutConfig::load(config_path)?;
+
+    if args.lba != 512 && args.lba != 4096 {
+        anyhow::bail!("LBA must be 512 or 4096");
+    }
+
+    let pad_bytes = args.pad_mib * 1024 * 1024;
+    let padding_lbas = pad_bytes / args.lba;
+    let footer_lbas = (1024 * 1024) / args.lba;
+
+    // If a target disk size was given, growing partitions absorb everything up
+    // to that size minus the footer reservation. Otherwise the disk is sized
+    // to exactly fit the fixed-size layout (a grow partition then fails to plan).
+    // The budget is the last LBA before the footer, inclusive.
+    let end_lba_budget = args
+        .disk_size_mib
+        .map(|mib| ((mib * 1024 * 1024) / args.lba).saturating_sub(footer_lbas + 1));
+
+    let planned =
+        layout::plan_partitions(&layout_config.partitions, padding_lbas, args.lba, end_lba_budget)?;
+    let last = planned
+        .last()
+        .context("Layout produced no partitions")?;
+    let total_lbas = match args.disk_size_mib {
+        Some(mib) => (mib * 1024 * 1024) / args.lba,
+        None => last.last_lba + 1 + footer_lbas,
+    };
+    let total_bytes = total_lbas * args.lba;
+
+    println!("Configuration (from {}):", config_path.display());
+    println!("  Padding:      {} MiB ({} sectors)", args.pad_mib, padding_lbas);
+    println!("  Partitions:   {}", planned.len());
+    println!("  Total Size:   {} bytes", total_bytes);
+
+    let output_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&args.output)
+        .context("Failed to create output file")?;
+    output_file
+        .set_len(total_bytes)
+        .context("Failed to set output file length")?;
+    drop(output_file);
+
+    println!("Writing GPT Partition Table...");
+    let lb_size = LogicalBlockSize::try_from(args.lba)
+        .context("Unsupported LBA size (must be 512 or 4096)")?;
+    let gpt_config = GptConfig::new().writable(true).logical_block_size(lb_size);
+    let mut disk = gpt_config
+        .open(&args.output)
+        .context("Failed to open output for GPT")?;
+    disk.update_partitions(BTreeMap::new())
+        .context("Failed to initialize GPT header")?;
+
+    if let Some(seed) = &args.seed {
+        disk.update_guid(Some(seed::derive_uuid(seed, "disk")));
+    }
+
+    for part in &planned {
+        let _ = disk
+            .add_partition(
+                &part.label,
+                part.size_bytes(args.lba),
+                part.type_guid.clone(),
+                0,
+                None,
+            )
+            .with_context(|| format!("Failed to add partition entry for '{}'", part.label))?;
+    }
+
+    // add_partition's own placement doesn't know about our 1-MiB-aligned plan,
+    // so pin every entry's LBAs to exactly what we calculated.
+    let mut partitions = disk.partitions().clone();
+    for (idx, part) in planned.iter().enumerate() {
+        let entry = partitions
+            .get_mut(&((idx + 1) as u32))
+            .with_context(|| format!("Partition entry {} not found", idx + 1))?;
+        entry.first_lba = part.first_lba;
+        entry.last_lba = part.last_lba;
+        if let Some(seed) = &args.seed {
+            entry.part_guid = seed::derive_uuid(seed, &format!("partition:{}", part.label));
+        }
+    }
+    disk.update_partitions(partitions.clone())
+        .context("Failed to update partitions")?;
+    disk.write().context("Failed to write GPT changes to disk")?;
+
+    println!("Populating partitions...");
+    for part in &planned {
+        populate_partition(&args.output, part, args.lba)?;
+    }
+
+    println!("Done! Created {}", args.output.display());
+
+    println!("\nRecommended /etc/fstab entries:");
+    println!("# <file system> <mount point> <type> <options> <dump> <pass>");
+    println!("proc /proc proc defaults 0 0");
+    for (idx, part) in planned.iter().enumerate() {
+        let part_guid = partitions
+            .get(&((idx + 1) as u32))
+            .map(|p| p.part_guid)
+            .unwrap_or_default();
+        if let Some(line) = fstab_line_for(part, part_guid) {
+            println!("{}", line);
+        } else {
+            println!(
+                "# PARTUUID={} for '{}' (unrecognized type, mount manually)",
+                part_guid, part.label
+            );
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,32 @@
+//! Deterministic UUID derivation for `--seed`, mirroring systemd-repart's seeded
+//! UUID mode: the same seed plus the same set of role labels always produces the
+//! same disk and partition GUIDs, so a layout can be rebuilt byte-for-byte and
+//! scripts can reference `PARTUUID=` before the image even exists.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Derive a UUIDv4-shaped, seed-deterministic GUID for a given role.
+///
+/// `role` identifies what's being named (e.g. `"disk"`, `"esp"`, or
+/// `"partition:<label>"`) so that every GUID in the image comes from the same
+/// seed but none of them collide.
+pub fn derive_uuid(seed: &Uuid, role: &str) -> Uuid {
+    let mut mac = HmacSha256::new_from_slice(seed.as_bytes())
+        .expect("HMAC-SHA256 accepts keys of any length");
+    mac.update(role.as_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+
+    // Force the version (4, "random") and variant (RFC 4122) nibbles so the
+    // result is a well-formed UUID, same as a true random UUIDv4 would be.
+    bytes[6] = (bytes[6] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+    Uuid::from_bytes(bytes)
+}
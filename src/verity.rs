@@ -0,0 +1,152 @@
+//! dm-verity Merkle tree construction for `--verity`.
+//!
+//! Builds a bottom-up hash tree over a data partition's contents: level 0 is
+//! `SHA256(salt || block)` for every 4096-byte data block (zero-padded in the
+//! last block), packed 128-to-a-block; each subsequent level hashes the
+//! previous level's blocks the same way, until a single block remains. That
+//! block's own `SHA256(salt || block)` is the root hash. A small superblock
+//! describing the tree is prepended to what's written into the hash partition.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+pub const BLOCK_SIZE: u64 = 4096;
+pub const DIGEST_SIZE: usize = 32;
+const HASHES_PER_BLOCK: usize = (BLOCK_SIZE as usize) / DIGEST_SIZE;
+const SIGNATURE: &[u8; 8] = b"verity\0\0";
+const ALGORITHM: &[u8] = b"sha256";
+
+/// A built Merkle tree: every level's packed hash blocks (bottom-up) plus the
+/// resulting root hash.
+pub struct VerityTree {
+    pub root_hash: [u8; DIGEST_SIZE],
+    pub salt: Vec<u8>,
+    pub data_block_count: u64,
+    levels: Vec<Vec<u8>>,
+}
+
+impl VerityTree {
+    /// Build the tree over `data_len` bytes read from `reader`.
+    pub fn build<R: Read>(mut reader: R, data_len: u64, salt: Vec<u8>) -> Result<VerityTree> {
+        let data_block_count = data_len.div_ceil(BLOCK_SIZE).max(1);
+
+        // Level 0: hash every data block.
+        let mut level = Vec::with_capacity(hash_block_count(data_block_count) as usize * BLOCK_SIZE as usize);
+        let mut digests = Vec::with_capacity(data_block_count as usize * DIGEST_SIZE);
+        let mut buf = vec![0u8; BLOCK_SIZE as usize];
+        for _ in 0..data_block_count {
+            buf.fill(0);
+            let mut filled = 0usize;
+            while filled < buf.len() {
+                let read = reader
+                    .read(&mut buf[filled..])
+                    .context("Failed to read data block while building verity hash tree")?;
+                if read == 0 {
+                    break; // short final block: the rest stays zero-padded
+                }
+                filled += read;
+            }
+            digests.extend_from_slice(&hash_block(&salt, &buf));
+        }
+        pack_into_blocks(&digests, &mut level);
+
+        let mut levels = vec![level];
+
+        // Recurse until a single block remains.
+        while levels.last().unwrap().len() as u64 > BLOCK_SIZE {
+            let prev = levels.last().unwrap();
+            let block_count = prev.len() as u64 / BLOCK_SIZE;
+            let mut digests = Vec::with_capacity(block_count as usize * DIGEST_SIZE);
+            for block in prev.chunks_exact(BLOCK_SIZE as usize) {
+                digests.extend_from_slice(&hash_block(&salt, block));
+            }
+            let mut next = Vec::new();
+            pack_into_blocks(&digests, &mut next);
+            levels.push(next);
+        }
+
+        let root_block = levels.last().unwrap();
+        let root_hash = hash_block(&salt, root_block);
+
+        Ok(VerityTree {
+            root_hash,
+            salt,
+            data_block_count,
+            levels,
+        })
+    }
+
+    /// Hex-encoded root hash, as printed to the user and used in sample fstab /
+    /// kernel command line snippets.
+    pub fn root_hash_hex(&self) -> String {
+        self.root_hash.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Total size (bytes) of the superblock plus every hash level, i.e. how big
+    /// the hash partition needs to be.
+    pub fn partition_size_bytes(&self) -> u64 {
+        BLOCK_SIZE + self.levels.iter().map(|l| l.len() as u64).sum::<u64>()
+    }
+
+    /// Serialize the superblock followed by every level (bottom-up), ready to be
+    /// written directly into the hash partition.
+    pub fn to_partition_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.partition_size_bytes() as usize);
+        out.extend_from_slice(&self.superblock_bytes());
+        for level in &self.levels {
+            out.extend_from_slice(level);
+        }
+        out
+    }
+
+    fn superblock_bytes(&self) -> [u8; BLOCK_SIZE as usize] {
+        let mut sb = [0u8; BLOCK_SIZE as usize];
+        let mut offset = 0;
+
+        sb[offset..offset + 8].copy_from_slice(SIGNATURE);
+        offset += 8;
+
+        sb[offset..offset + 4].copy_from_slice(&1u32.to_le_bytes()); // version
+        offset += 4;
+
+        sb[offset..offset + ALGORITHM.len()].copy_from_slice(ALGORITHM);
+        offset += 32; // fixed-width algorithm name field
+
+        sb[offset..offset + 4].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes()); // data_block_size
+        offset += 4;
+        sb[offset..offset + 4].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes()); // hash_block_size
+        offset += 4;
+
+        sb[offset..offset + 8].copy_from_slice(&self.data_block_count.to_le_bytes());
+        offset += 8;
+
+        sb[offset..offset + 2].copy_from_slice(&(self.salt.len() as u16).to_le_bytes());
+        offset += 2;
+
+        sb[offset..offset + self.salt.len()].copy_from_slice(&self.salt);
+
+        sb
+    }
+}
+
+fn hash_block(salt: &[u8], block: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(block);
+    hasher.finalize().into()
+}
+
+/// How many 4096-byte blocks are needed to pack `block_count` 32-byte digests,
+/// 128 per block.
+fn hash_block_count(block_count: u64) -> u64 {
+    (block_count as usize).div_ceil(HASHES_PER_BLOCK).max(1) as u64
+}
+
+/// Pack a flat run of digests into zero-padded 4096-byte blocks, 128 digests each.
+fn pack_into_blocks(digests: &[u8], out: &mut Vec<u8>) {
+    let digest_count = digests.len() / DIGEST_SIZE;
+    let block_count = hash_block_count(digest_count as u64);
+    out.resize(block_count as usize * BLOCK_SIZE as usize, 0);
+    out[..digests.len()].copy_from_slice(digests);
+}